@@ -0,0 +1,151 @@
+//! Protocol types and signature verification for the opt-in end-to-end
+//! encrypted session mode.
+//!
+//! The relay never holds the X25519 shared secret or the AES-256-GCM key
+//! derived from it, so it cannot decrypt `data` frames; the ECDH exchange
+//! and symmetric encryption live entirely on the Horizon and Voyager
+//! clients. The one thing the relay does here is check the Horizon's
+//! `ed25519` signature over the two `kx_init`/`kx_reply` pubkeys so that the
+//! pubkey it surfaces via `SessionStatus` for pinning is one the Horizon
+//! actually signed, not whatever the last message on the wire claimed.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+
+pub const KX_INIT: &str = "kx_init";
+pub const KX_REPLY: &str = "kx_reply";
+pub const DATA: &str = "data";
+
+/// `{"type":"kx_init","pub":<base64 x25519 pubkey>}` sent by a Voyager.
+#[derive(Debug, Deserialize)]
+pub struct KxInit {
+    #[serde(rename = "pub")]
+    pub x25519_pub: String,
+}
+
+/// `{"type":"kx_reply","pub":...,"ed_pub":...,"sig":...}` sent by the
+/// Horizon in response to a `KxInit`.
+#[derive(Debug, Deserialize)]
+pub struct KxReply {
+    #[serde(rename = "pub")]
+    pub x25519_pub: String,
+    pub ed_pub: String,
+    pub sig: String,
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Encoding(&'static str),
+    BadSignature,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Encoding(field) => write!(f, "invalid base64 in {field}"),
+            CryptoError::BadSignature => write!(f, "signature verification failed"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Verifies that `reply.sig` is a valid ed25519 signature, made by
+/// `reply.ed_pub`, over the concatenation of the Voyager's and Horizon's
+/// X25519 public keys (in that order). Returns `Ok(())` on success; the
+/// decoded keys are not returned.
+pub fn verify_kx_reply(voyager_x25519_pub_b64: &str, reply: &KxReply) -> Result<(), CryptoError> {
+    let voyager_pub = BASE64
+        .decode(voyager_x25519_pub_b64)
+        .map_err(|_| CryptoError::Encoding("voyager pub"))?;
+    let horizon_pub = BASE64
+        .decode(&reply.x25519_pub)
+        .map_err(|_| CryptoError::Encoding("horizon pub"))?;
+    let ed_pub_bytes = BASE64
+        .decode(&reply.ed_pub)
+        .map_err(|_| CryptoError::Encoding("ed25519 pub"))?;
+    let sig_bytes = BASE64
+        .decode(&reply.sig)
+        .map_err(|_| CryptoError::Encoding("signature"))?;
+
+    let ed_pub_array: [u8; 32] = ed_pub_bytes
+        .try_into()
+        .map_err(|_| CryptoError::Encoding("ed25519 pub"))?;
+    let verifying_key =
+        VerifyingKey::from_bytes(&ed_pub_array).map_err(|_| CryptoError::Encoding("ed25519 pub"))?;
+    let sig_array: [u8; 64] = sig_bytes
+        .try_into()
+        .map_err(|_| CryptoError::Encoding("signature"))?;
+    let signature = Signature::from_bytes(&sig_array);
+
+    let mut signed = voyager_pub;
+    signed.extend_from_slice(&horizon_pub);
+
+    verifying_key
+        .verify(&signed, &signature)
+        .map_err(|_| CryptoError::BadSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const VOYAGER_PUB: &[u8] = b"voyager-x25519-pubkey-32-bytes!!";
+    const HORIZON_PUB: &[u8] = b"horizon-x25519-pubkey-32-bytes!!";
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn valid_reply() -> (String, KxReply) {
+        let key = signing_key();
+        let mut signed = VOYAGER_PUB.to_vec();
+        signed.extend_from_slice(HORIZON_PUB);
+        let sig = key.sign(&signed);
+        let reply = KxReply {
+            x25519_pub: BASE64.encode(HORIZON_PUB),
+            ed_pub: BASE64.encode(key.verifying_key().to_bytes()),
+            sig: BASE64.encode(sig.to_bytes()),
+        };
+        (BASE64.encode(VOYAGER_PUB), reply)
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let (voyager_pub_b64, reply) = valid_reply();
+        assert!(verify_kx_reply(&voyager_pub_b64, &reply).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_voyager_pubkey() {
+        let (_, reply) = valid_reply();
+        let wrong_voyager_pub_b64 = BASE64.encode(b"someone-elses-x25519-pubkey-32b!");
+        assert!(matches!(
+            verify_kx_reply(&wrong_voyager_pub_b64, &reply),
+            Err(CryptoError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let (voyager_pub_b64, mut reply) = valid_reply();
+        reply.sig = BASE64.encode([0u8; 64]);
+        assert!(matches!(
+            verify_kx_reply(&voyager_pub_b64, &reply),
+            Err(CryptoError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        let (_, mut reply) = valid_reply();
+        reply.x25519_pub = "not valid base64!!".to_string();
+        assert!(matches!(
+            verify_kx_reply(&BASE64.encode(VOYAGER_PUB), &reply),
+            Err(CryptoError::Encoding("horizon pub"))
+        ));
+    }
+}
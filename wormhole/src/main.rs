@@ -1,6 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::{Path, Query, State};
@@ -11,9 +13,20 @@ use futures_util::{SinkExt, StreamExt};
 use rand::Rng;
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json::Value;
-use tokio::sync::{mpsc, Mutex};
+use serde_json::{Map, Value};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{info, warn};
+use uuid::Uuid;
+
+mod crypto;
+
+/// Maximum number of messages buffered for a detached Horizon or for each
+/// detached Voyager; once full, the oldest buffered message is dropped to
+/// bound memory.
+const DETACH_BUFFER_LIMIT: usize = 256;
+
+/// How often the registry scans for sessions past `WORMHOLE_IDLE_TIMEOUT`.
+const IDLE_SCAN_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Role {
@@ -21,24 +34,778 @@ enum Role {
     Voyager,
 }
 
+/// Wire framing negotiated per-connection via `?codec=`. Horizon-to-Voyager
+/// payloads are forwarded verbatim regardless of codec. A Voyager-to-Horizon
+/// frame that decodes as an object gets a `from` field added and is
+/// re-encoded in the Horizon's codec; other Voyager frames are forwarded
+/// verbatim. Frames the server itself originates (`session_assigned`,
+/// `voyager_joined`/`voyager_left`, the `horizon_offline` error) are encoded
+/// to match the receiving connection's codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Json,
+    MsgPack,
+}
+
+impl Codec {
+    fn from_param(codec: Option<&str>) -> Self {
+        match codec {
+            Some("msgpack") => Codec::MsgPack,
+            _ => Codec::Json,
+        }
+    }
+}
+
+/// Encodes a server-originated control frame for `codec`.
+fn encode_frame(codec: Codec, value: &Value) -> Message {
+    match codec {
+        Codec::Json => Message::Text(value.to_string()),
+        Codec::MsgPack => Message::Binary(rmp_serde::to_vec(value).unwrap_or_default()),
+    }
+}
+
+/// Tracks a side of a `Session` that has disconnected but is still within
+/// its reconnect grace window: messages destined for it are buffered rather
+/// than dropped, and `cancel` lets a reconnect abort the expiry timer.
+#[derive(Debug)]
+struct DetachState {
+    since: Instant,
+    buffer: VecDeque<Message>,
+    cancel: oneshot::Sender<()>,
+}
+
+impl DetachState {
+    fn buffer(&mut self, msg: Message) {
+        if self.buffer.len() >= DETACH_BUFFER_LIMIT {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(msg);
+    }
+}
+
 #[derive(Debug)]
 struct Session {
     horizon: Option<mpsc::UnboundedSender<Message>>,
-    voyagers: Vec<mpsc::UnboundedSender<Message>>,
+    horizon_detach: Option<DetachState>,
+    voyagers: HashMap<Uuid, mpsc::UnboundedSender<Message>>,
+    /// One detach slot per Voyager identity, so a reconnect only drains the
+    /// buffer belonging to that same Voyager instead of whichever Voyager
+    /// happens to connect next.
+    voyagers_detach: HashMap<Uuid, DetachState>,
+    /// Set once a `kx_init`/`kx_reply` handshake is observed on this
+    /// session; once true, only handshake and `data` (ciphertext) frames
+    /// are relayed so plaintext can't slip through alongside it.
+    encrypted: bool,
+    /// The X25519 pubkey from the most recent `kx_init` a Voyager sent,
+    /// kept around just long enough to verify the Horizon's `kx_reply`
+    /// signature over it. Encrypted mode only supports one Voyager
+    /// performing the handshake at a time per session, since there's
+    /// nothing in `kx_reply` correlating it back to a specific Voyager's
+    /// `kx_init`; a second `kx_init` while one is already pending is
+    /// rejected rather than silently overwriting this and breaking the
+    /// first Voyager's handshake.
+    pending_kx_init_pub: Option<String>,
+    /// The Horizon's ed25519 identity key, once its `kx_reply` signature
+    /// has been verified. Surfaced in `SessionStatus` so Voyagers can pin it.
+    horizon_ed25519_pub: Option<String>,
+    /// SHA-256 hash of the per-session join secret set by the Horizon via
+    /// `set_join_secret`/`rotate_join_secret`. `None` means any Voyager may
+    /// join (subject to `max_voyagers`).
+    join_secret_hash: Option<String>,
+    /// Optional cap on concurrent Voyagers, set by the Horizon alongside
+    /// the join secret.
+    max_voyagers: Option<usize>,
+    /// Stamped on every relayed message; sessions with no traffic for
+    /// `WORMHOLE_IDLE_TIMEOUT` are reaped by the registry's idle scan.
+    last_activity: Instant,
+    /// Codec of the currently-connected Horizon, used to encode
+    /// server-originated frames sent to it. Meaningless while `horizon` is
+    /// `None`.
+    horizon_codec: Codec,
+    /// Per-Voyager codec, keyed the same as `voyagers`.
+    voyager_codecs: HashMap<Uuid, Codec>,
 }
 
 impl Session {
     fn new() -> Self {
         Self {
             horizon: None,
-            voyagers: Vec::new(),
+            horizon_detach: None,
+            voyagers: HashMap::new(),
+            voyagers_detach: HashMap::new(),
+            encrypted: false,
+            pending_kx_init_pub: None,
+            horizon_ed25519_pub: None,
+            join_secret_hash: None,
+            max_voyagers: None,
+            last_activity: Instant::now(),
+            horizon_codec: Codec::Json,
+            voyager_codecs: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.horizon.is_none()
+            && self.horizon_detach.is_none()
+            && self.voyagers.is_empty()
+            && self.voyagers_detach.is_empty()
+    }
+}
+
+/// Commands accepted by the session registry task. The registry owns the
+/// `HashMap<String, Session>` outright; every other task talks to it through
+/// this channel instead of locking a shared mutex.
+#[derive(Debug)]
+enum SessionCommand {
+    Register {
+        id: Option<String>,
+        role: Role,
+        /// A Voyager reconnecting within its grace window presents the id it
+        /// was previously assigned so it can reclaim its buffered messages;
+        /// unused for a Horizon. The registry only honors this if it matches
+        /// a live detach entry, otherwise it mints a fresh id, so a claim
+        /// can't be used to hijack another Voyager's buffer.
+        claimed_voyager_id: Option<Uuid>,
+        codec: Codec,
+        tx: mpsc::UnboundedSender<Message>,
+        reply: oneshot::Sender<RegisterAck>,
+    },
+    Route {
+        id: String,
+        role: Role,
+        /// The sending Voyager's id, used to tag the frame forwarded to the
+        /// Horizon. `None` for a Horizon, which targets a specific Voyager
+        /// through the frame's `to` field instead.
+        voyager_id: Option<Uuid>,
+        msg: Message,
+        origin: Option<mpsc::UnboundedSender<Message>>,
+    },
+    Remove {
+        id: String,
+        role: Role,
+        voyager_id: Option<Uuid>,
+        tx: mpsc::UnboundedSender<Message>,
+    },
+    /// Fired by a detach timer once a reconnect grace window elapses without
+    /// the corresponding side reattaching.
+    ExpireDetach {
+        id: String,
+        role: Role,
+        /// Which Voyager's detach slot expired; unused for a Horizon.
+        voyager_id: Option<Uuid>,
+    },
+    List {
+        reply: oneshot::Sender<Vec<SessionStatus>>,
+    },
+    Get {
+        id: String,
+        reply: oneshot::Sender<Option<SessionStatus>>,
+    },
+    Close {
+        id: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Live counts derived from the registry's current state, for the
+    /// `wormhole_sessions_active`/`_horizons_connected`/`_voyagers_connected`
+    /// gauges. Unlike the counters in `Metrics`, these aren't tracked
+    /// incrementally — they're read straight off `sessions` so they can
+    /// never drift from reality.
+    Metrics {
+        reply: oneshot::Sender<RegistryMetrics>,
+    },
+    /// Checked by `ws_handler` before upgrading a Voyager, so a bad join
+    /// secret is rejected before the socket ever opens. `max_voyagers` is
+    /// *not* checked here — two concurrent joiners could both pass this
+    /// check before either registers, so the cap is instead enforced
+    /// atomically inside `Register` itself.
+    CheckJoin {
+        id: String,
+        join: Option<String>,
+        reply: oneshot::Sender<JoinCheck>,
+    },
+    /// Sent periodically by a background timer; closes and drops any
+    /// session whose `last_activity` is older than `idle_timeout`.
+    ReapIdle { idle_timeout: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JoinCheck {
+    Allowed,
+    SecretMismatch,
+}
+
+/// Reply to `SessionCommand::Register`. `voyager_id` is the id the registry
+/// actually assigned (reclaimed from a detach slot or freshly minted); it's
+/// `None` for a Horizon. `over_capacity` is `true` if a Voyager's join was
+/// rejected because `max_voyagers` was already reached, in which case
+/// `voyager_id` is `None` and the caller must close the connection instead
+/// of proceeding.
+#[derive(Debug)]
+struct RegisterAck {
+    session_id: String,
+    voyager_id: Option<Uuid>,
+    over_capacity: bool,
+}
+
+fn hash_secret(secret: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn check_join(session: &Session, join: Option<&str>) -> JoinCheck {
+    if let Some(expected) = session.join_secret_hash.as_deref() {
+        if join.map(hash_secret).as_deref() != Some(expected) {
+            return JoinCheck::SecretMismatch;
+        }
+    }
+    JoinCheck::Allowed
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RegistryMetrics {
+    sessions_active: usize,
+    horizons_connected: usize,
+    voyagers_connected: usize,
+}
+
+/// Monotonic counters for the `/metrics` endpoint. Updated from inside the
+/// registry task as messages are routed, so they stay consistent with the
+/// `Route` command handling that produces them.
+#[derive(Debug, Default)]
+struct Metrics {
+    messages_relayed_horizon_to_voyager: AtomicU64,
+    messages_relayed_voyager_to_horizon: AtomicU64,
+    bytes_relayed: AtomicU64,
+    horizon_offline_replies: AtomicU64,
+}
+
+fn message_len(msg: &Message) -> u64 {
+    match msg {
+        Message::Text(text) => text.len() as u64,
+        Message::Binary(data) => data.len() as u64,
+        _ => 0,
+    }
+}
+
+/// Spawns a timer that, unless cancelled first, sends `ExpireDetach` for
+/// `(id, role)` back to the registry once `timeout` elapses.
+fn start_detach_timer(
+    registry: mpsc::UnboundedSender<SessionCommand>,
+    id: String,
+    role: Role,
+    voyager_id: Option<Uuid>,
+    timeout: Duration,
+) -> oneshot::Sender<()> {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {
+                let _ = registry.send(SessionCommand::ExpireDetach { id, role, voyager_id });
+            }
+            _ = cancel_rx => {}
         }
+    });
+    cancel_tx
+}
+
+/// Spawns the task that owns the session registry and returns a handle for
+/// sending it commands. Running the registry as a single owner task (rather
+/// than behind a `Mutex`) means the hot broadcast path in `route_message`
+/// never contends with unrelated sessions.
+fn spawn_registry(
+    reconnect_timeout: Duration,
+    idle_timeout: Duration,
+    metrics: Arc<Metrics>,
+) -> mpsc::UnboundedSender<SessionCommand> {
+    let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel::<SessionCommand>();
+    let self_tx = cmd_tx.clone();
+
+    {
+        let reap_tx = self_tx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_SCAN_INTERVAL);
+            loop {
+                interval.tick().await;
+                let _ = reap_tx.send(SessionCommand::ReapIdle { idle_timeout });
+            }
+        });
     }
+
+    tokio::spawn(async move {
+        let mut sessions: HashMap<String, Session> = HashMap::new();
+
+        while let Some(cmd) = cmd_rx.recv().await {
+            match cmd {
+                SessionCommand::Register {
+                    id,
+                    role,
+                    claimed_voyager_id,
+                    codec,
+                    tx,
+                    reply,
+                } => {
+                    let id = id.unwrap_or_else(|| generate_session_id(&sessions));
+                    let session = sessions.entry(id.clone()).or_insert_with(Session::new);
+                    let mut assigned_voyager_id = None;
+                    let mut over_capacity = false;
+                    match role {
+                        Role::Horizon => {
+                            if let Some(mut detach) = session.horizon_detach.take() {
+                                let _ = detach.cancel.send(());
+                                for msg in detach.buffer.drain(..) {
+                                    let _ = tx.send(msg);
+                                }
+                                info!(session_id = %id, detached_for_ms = %detach.since.elapsed().as_millis(), "horizon reattached within grace window");
+                            } else if session.horizon.is_some() {
+                                warn!(session_id = %id, "horizon replaced existing connection");
+                            }
+                            session.horizon = Some(tx);
+                            session.horizon_codec = codec;
+                        }
+                        Role::Voyager => {
+                            // Only reclaim a claimed id if it actually owns a
+                            // live detach slot; otherwise an unrelated
+                            // Voyager could claim someone else's buffer. A
+                            // reclaim isn't a new join, so it's exempt from
+                            // the cap below.
+                            let reclaim = claimed_voyager_id
+                                .filter(|candidate| session.voyagers_detach.contains_key(candidate));
+                            if reclaim.is_none()
+                                && session
+                                    .max_voyagers
+                                    .is_some_and(|cap| session.voyagers.len() >= cap)
+                            {
+                                // Checked and enforced in the same step the
+                                // actor processes this command, so two
+                                // concurrent joins can't both slip in over
+                                // the cap the way a separate pre-upgrade
+                                // CheckJoin round trip would allow.
+                                over_capacity = true;
+                            } else {
+                                let voyager_id = reclaim.unwrap_or_else(Uuid::new_v4);
+                                if let Some(mut detach) = session.voyagers_detach.remove(&voyager_id)
+                                {
+                                    let _ = detach.cancel.send(());
+                                    for msg in detach.buffer.drain(..) {
+                                        let _ = tx.send(msg);
+                                    }
+                                    info!(session_id = %id, %voyager_id, detached_for_ms = %detach.since.elapsed().as_millis(), "voyager reattached within grace window");
+                                }
+                                session.voyagers.insert(voyager_id, tx);
+                                session.voyager_codecs.insert(voyager_id, codec);
+                                if let Some(horizon) = session.horizon.as_ref() {
+                                    let _ = horizon.send(encode_frame(
+                                        session.horizon_codec,
+                                        &serde_json::json!({
+                                            "v": 1,
+                                            "type": "voyager_joined",
+                                            "id": voyager_id.to_string(),
+                                        }),
+                                    ));
+                                }
+                                assigned_voyager_id = Some(voyager_id);
+                            }
+                        }
+                    }
+                    let _ = reply.send(RegisterAck {
+                        session_id: id,
+                        voyager_id: assigned_voyager_id,
+                        over_capacity,
+                    });
+                }
+                SessionCommand::Route {
+                    id,
+                    role,
+                    voyager_id,
+                    msg,
+                    origin,
+                } => {
+                    let Some(session) = sessions.get_mut(&id) else {
+                        continue;
+                    };
+                    session.last_activity = Instant::now();
+
+                    let frame = parse_frame(&msg);
+                    let typ = frame.as_ref().and_then(frame_type).map(str::to_string);
+
+                    match typ.as_deref() {
+                        Some(crypto::KX_INIT) if role == Role::Voyager => {
+                            session.encrypted = true;
+                            if session.pending_kx_init_pub.is_some() {
+                                warn!(
+                                    session_id = %id,
+                                    ?voyager_id,
+                                    "rejecting kx_init: another voyager's handshake is already pending"
+                                );
+                                continue;
+                            }
+                            match frame.as_ref().map(|m| {
+                                serde_json::from_value::<crypto::KxInit>(Value::Object(m.clone()))
+                            }) {
+                                Some(Ok(kx_init)) => {
+                                    session.pending_kx_init_pub = Some(kx_init.x25519_pub);
+                                }
+                                Some(Err(_)) => warn!(session_id = %id, "malformed kx_init frame"),
+                                None => {}
+                            }
+                        }
+                        Some(crypto::KX_REPLY) if role == Role::Horizon => {
+                            session.encrypted = true;
+                            if let (Some(map), Some(voyager_pub)) =
+                                (frame.as_ref(), session.pending_kx_init_pub.clone())
+                            {
+                                match serde_json::from_value::<crypto::KxReply>(Value::Object(
+                                    map.clone(),
+                                )) {
+                                    Ok(kx_reply) => {
+                                        match crypto::verify_kx_reply(&voyager_pub, &kx_reply) {
+                                            Ok(()) => {
+                                                session.horizon_ed25519_pub =
+                                                    Some(kx_reply.ed_pub.clone());
+                                                session.pending_kx_init_pub = None;
+                                            }
+                                            Err(err) => {
+                                                warn!(session_id = %id, %err, "rejected kx_reply with invalid signature");
+                                            }
+                                        }
+                                    }
+                                    Err(_) => warn!(session_id = %id, "malformed kx_reply frame"),
+                                }
+                            }
+                        }
+                        Some(crypto::KX_INIT) | Some(crypto::KX_REPLY) => {
+                            warn!(session_id = %id, ?role, ?typ, "ignoring handshake frame from the wrong role");
+                            continue;
+                        }
+                        Some("set_join_secret") | Some("rotate_join_secret") => {
+                            if role != Role::Horizon {
+                                warn!(
+                                    session_id = %id,
+                                    ?role,
+                                    "ignoring join-secret control frame from a non-Horizon connection"
+                                );
+                                continue;
+                            }
+                            if let Some(Value::String(secret)) =
+                                frame.as_ref().and_then(|m| m.get("secret"))
+                            {
+                                session.join_secret_hash = Some(hash_secret(secret));
+                            }
+                            if let Some(cap) =
+                                frame.as_ref().and_then(|m| m.get("max_voyagers"))
+                            {
+                                session.max_voyagers = cap.as_u64().map(|n| n as usize);
+                            }
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    if session.encrypted {
+                        let allowed = matches!(
+                            typ.as_deref(),
+                            Some(crypto::KX_INIT) | Some(crypto::KX_REPLY) | Some(crypto::DATA)
+                        );
+                        if !allowed {
+                            warn!(session_id = %id, ?typ, "dropping plaintext frame on encrypted session");
+                            continue;
+                        }
+                    }
+
+                    // Counters below only fire once a send or buffer actually
+                    // happens, so "relayed" doesn't include messages dropped
+                    // for having nowhere to go.
+                    match role {
+                        Role::Horizon => {
+                            // A "to" field targets one Voyager instead of the whole group.
+                            let target = frame
+                                .as_ref()
+                                .and_then(|m| m.get("to"))
+                                .and_then(Value::as_str)
+                                .and_then(|s| Uuid::parse_str(s).ok());
+                            let msg_len = message_len(&msg);
+                            let delivered = if let Some(target) = target {
+                                if let Some(tx) = session.voyagers.get(&target) {
+                                    if tx.send(msg).is_ok() {
+                                        true
+                                    } else {
+                                        session.voyagers.remove(&target);
+                                        false
+                                    }
+                                } else if let Some(detach) = session.voyagers_detach.get_mut(&target)
+                                {
+                                    // Target is within its reconnect grace
+                                    // window rather than gone for good.
+                                    detach.buffer(msg);
+                                    true
+                                } else {
+                                    if let Some(origin) = origin.as_ref() {
+                                        let _ = origin.send(encode_frame(
+                                            session.horizon_codec,
+                                            &serde_json::json!({
+                                                "v": 1,
+                                                "type": "error",
+                                                "code": "voyager_not_found",
+                                                "id": target.to_string(),
+                                            }),
+                                        ));
+                                    }
+                                    false
+                                }
+                            } else {
+                                let mut delivered = false;
+                                session.voyagers.retain(|_, tx| {
+                                    let sent = tx.send(msg.clone()).is_ok();
+                                    delivered |= sent;
+                                    sent
+                                });
+                                if !session.voyagers_detach.is_empty() {
+                                    for detach in session.voyagers_detach.values_mut() {
+                                        detach.buffer(msg.clone());
+                                    }
+                                    delivered = true;
+                                }
+                                delivered
+                            };
+                            if delivered {
+                                metrics.bytes_relayed.fetch_add(msg_len, Ordering::Relaxed);
+                                metrics
+                                    .messages_relayed_horizon_to_voyager
+                                    .fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        Role::Voyager => {
+                            // Tag the frame with the sending Voyager's id so the
+                            // Horizon knows who to reply to via "to", re-encoding
+                            // it in the Horizon's own codec since it's no longer
+                            // the untouched bytes the Voyager sent.
+                            let msg = match (voyager_id, frame) {
+                                (Some(voyager_id), Some(mut map)) => {
+                                    map.insert(
+                                        "from".to_string(),
+                                        Value::String(voyager_id.to_string()),
+                                    );
+                                    encode_frame(session.horizon_codec, &Value::Object(map))
+                                }
+                                _ => msg,
+                            };
+                            let msg_len = message_len(&msg);
+                            let delivered = if let Some(horizon) = session.horizon.as_ref() {
+                                if horizon.send(msg).is_ok() {
+                                    true
+                                } else {
+                                    session.horizon = None;
+                                    false
+                                }
+                            } else if let Some(detach) = session.horizon_detach.as_mut() {
+                                detach.buffer(msg);
+                                true
+                            } else {
+                                if let Some(origin) = origin.as_ref() {
+                                    let origin_codec = voyager_id
+                                        .and_then(|id| session.voyager_codecs.get(&id).copied())
+                                        .unwrap_or(Codec::Json);
+                                    if let Some(reply) = build_no_horizon_reply(&msg, origin_codec) {
+                                        if origin.send(reply).is_ok() {
+                                            metrics
+                                                .horizon_offline_replies
+                                                .fetch_add(1, Ordering::Relaxed);
+                                        }
+                                    }
+                                }
+                                false
+                            };
+                            if delivered {
+                                metrics.bytes_relayed.fetch_add(msg_len, Ordering::Relaxed);
+                                metrics
+                                    .messages_relayed_voyager_to_horizon
+                                    .fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                }
+                SessionCommand::Remove {
+                    id,
+                    role,
+                    voyager_id,
+                    tx,
+                } => {
+                    let Some(session) = sessions.get_mut(&id) else {
+                        continue;
+                    };
+                    match role {
+                        Role::Horizon => {
+                            if let Some(horizon) = session.horizon.as_ref() {
+                                if horizon.same_channel(&tx) {
+                                    session.horizon = None;
+                                    let cancel = start_detach_timer(
+                                        self_tx.clone(),
+                                        id.clone(),
+                                        Role::Horizon,
+                                        None,
+                                        reconnect_timeout,
+                                    );
+                                    session.horizon_detach = Some(DetachState {
+                                        since: Instant::now(),
+                                        buffer: VecDeque::new(),
+                                        cancel,
+                                    });
+                                }
+                            }
+                        }
+                        Role::Voyager => {
+                            let voyager_id = voyager_id.expect("voyager_id set for Role::Voyager");
+                            session.voyager_codecs.remove(&voyager_id);
+                            if session.voyagers.remove(&voyager_id).is_some() {
+                                if let Some(horizon) = session.horizon.as_ref() {
+                                    let _ = horizon.send(encode_frame(
+                                        session.horizon_codec,
+                                        &serde_json::json!({
+                                            "v": 1,
+                                            "type": "voyager_left",
+                                            "id": voyager_id.to_string(),
+                                        }),
+                                    ));
+                                }
+                                // Each Voyager gets its own grace window, not
+                                // just the last one left in the group, so a
+                                // reconnect can only ever reclaim its own
+                                // buffer.
+                                let cancel = start_detach_timer(
+                                    self_tx.clone(),
+                                    id.clone(),
+                                    Role::Voyager,
+                                    Some(voyager_id),
+                                    reconnect_timeout,
+                                );
+                                session.voyagers_detach.insert(
+                                    voyager_id,
+                                    DetachState {
+                                        since: Instant::now(),
+                                        buffer: VecDeque::new(),
+                                        cancel,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                    if session.is_empty() {
+                        sessions.remove(&id);
+                    }
+                }
+                SessionCommand::ExpireDetach { id, role, voyager_id } => {
+                    let Some(session) = sessions.get_mut(&id) else {
+                        continue;
+                    };
+                    match role {
+                        Role::Horizon => {
+                            if session.horizon_detach.take().is_some() {
+                                info!(session_id = %id, "horizon grace window expired");
+                                for (_, voyager) in session.voyagers.drain() {
+                                    let _ = voyager.send(Message::Close(None));
+                                }
+                                session.voyagers_detach.clear();
+                            }
+                        }
+                        Role::Voyager => {
+                            let voyager_id =
+                                voyager_id.expect("voyager_id set for a Voyager ExpireDetach");
+                            if session.voyagers_detach.remove(&voyager_id).is_some() {
+                                info!(session_id = %id, %voyager_id, "voyager grace window expired");
+                            }
+                        }
+                    }
+                    if session.is_empty() {
+                        sessions.remove(&id);
+                    }
+                }
+                SessionCommand::List { reply } => {
+                    let statuses = sessions
+                        .iter()
+                        .map(|(session_id, session)| SessionStatus {
+                            session: session_id.clone(),
+                            horizon_connected: session.horizon.is_some(),
+                            voyager_count: session.voyagers.len(),
+                            voyager_ids: session.voyagers.keys().map(Uuid::to_string).collect(),
+                            encrypted: session.encrypted,
+                            horizon_ed25519_pub: session.horizon_ed25519_pub.clone(),
+                            idle_secs: session.last_activity.elapsed().as_secs(),
+                        })
+                        .collect();
+                    let _ = reply.send(statuses);
+                }
+                SessionCommand::Get { id, reply } => {
+                    let status = sessions.get(&id).map(|session| SessionStatus {
+                        session: id.clone(),
+                        horizon_connected: session.horizon.is_some(),
+                        voyager_count: session.voyagers.len(),
+                        voyager_ids: session.voyagers.keys().map(Uuid::to_string).collect(),
+                        encrypted: session.encrypted,
+                        horizon_ed25519_pub: session.horizon_ed25519_pub.clone(),
+                        idle_secs: session.last_activity.elapsed().as_secs(),
+                    });
+                    let _ = reply.send(status);
+                }
+                SessionCommand::Close { id, reply } => {
+                    let Some(session) = sessions.remove(&id) else {
+                        let _ = reply.send(false);
+                        continue;
+                    };
+                    if let Some(horizon) = session.horizon.as_ref() {
+                        let _ = horizon.send(Message::Close(None));
+                    }
+                    for (_, voyager) in session.voyagers {
+                        let _ = voyager.send(Message::Close(None));
+                    }
+                    let _ = reply.send(true);
+                }
+                SessionCommand::Metrics { reply } => {
+                    let registry_metrics = RegistryMetrics {
+                        sessions_active: sessions.len(),
+                        horizons_connected: sessions
+                            .values()
+                            .filter(|session| session.horizon.is_some())
+                            .count(),
+                        voyagers_connected: sessions.values().map(|session| session.voyagers.len()).sum(),
+                    };
+                    let _ = reply.send(registry_metrics);
+                }
+                SessionCommand::CheckJoin { id, join, reply } => {
+                    let result = match sessions.get(&id) {
+                        Some(session) => check_join(session, join.as_deref()),
+                        None => JoinCheck::Allowed,
+                    };
+                    let _ = reply.send(result);
+                }
+                SessionCommand::ReapIdle { idle_timeout } => {
+                    sessions.retain(|id, session| {
+                        if session.last_activity.elapsed() < idle_timeout {
+                            return true;
+                        }
+                        info!(session_id = %id, "reaping idle session");
+                        if let Some(horizon) = session.horizon.as_ref() {
+                            let _ = horizon.send(Message::Close(None));
+                        }
+                        for voyager in session.voyagers.values() {
+                            let _ = voyager.send(Message::Close(None));
+                        }
+                        false
+                    });
+                }
+            }
+        }
+    });
+
+    cmd_tx
 }
 
 #[derive(Clone)]
 struct AppState {
-    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    registry: mpsc::UnboundedSender<SessionCommand>,
+    metrics: Arc<Metrics>,
     token: Option<String>,
 }
 
@@ -47,6 +814,12 @@ struct WsParams {
     role: String,
     session: Option<String>,
     token: Option<String>,
+    join: Option<String>,
+    codec: Option<String>,
+    /// A reconnecting Voyager's previously-assigned id (from `voyager_assigned`),
+    /// presented so it can reclaim its buffered messages. Only honored if it
+    /// still owns a live detach slot.
+    voyager_id: Option<String>,
 }
 
 fn generate_session_id(existing: &HashMap<String, Session>) -> String {
@@ -65,6 +838,38 @@ fn generate_session_id(existing: &HashMap<String, Session>) -> String {
     }
 }
 
+fn reconnect_timeout() -> Duration {
+    let secs = std::env::var("WORMHOLE_RECONNECT_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+fn idle_timeout() -> Duration {
+    let secs = std::env::var("WORMHOLE_IDLE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600);
+    Duration::from_secs(secs)
+}
+
+fn ping_interval() -> Duration {
+    let secs = std::env::var("WORMHOLE_PING_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+fn pong_timeout() -> Duration {
+    let secs = std::env::var("WORMHOLE_PONG_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(90);
+    Duration::from_secs(secs)
+}
+
 #[derive(Debug, Deserialize)]
 struct AdminParams {
     token: Option<String>,
@@ -75,6 +880,10 @@ struct SessionStatus {
     session: String,
     horizon_connected: bool,
     voyager_count: usize,
+    voyager_ids: Vec<String>,
+    encrypted: bool,
+    horizon_ed25519_pub: Option<String>,
+    idle_secs: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -94,13 +903,16 @@ async fn main() {
     } else {
         warn!("wormhole token auth disabled");
     }
+    let metrics = Arc::new(Metrics::default());
     let state = AppState {
-        sessions: Arc::new(Mutex::new(HashMap::new())),
+        registry: spawn_registry(reconnect_timeout(), idle_timeout(), metrics.clone()),
+        metrics,
         token,
     };
 
     let app = Router::new()
         .route("/health", get(health))
+        .route("/metrics", get(metrics_handler))
         .route("/sessions", get(list_sessions))
         .route("/sessions/:id", get(get_session).delete(close_session))
         .route("/ws", get(ws_handler))
@@ -121,6 +933,55 @@ async fn health() -> &'static str {
     "ok"
 }
 
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .registry
+        .send(SessionCommand::Metrics { reply: reply_tx })
+        .is_err()
+    {
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "registry unavailable")
+            .into_response();
+    }
+    let registry_metrics = reply_rx.await.unwrap_or_default();
+    let metrics = &state.metrics;
+
+    let body = format!(
+        "# HELP wormhole_sessions_active Number of active relay sessions.\n\
+         # TYPE wormhole_sessions_active gauge\n\
+         wormhole_sessions_active {sessions_active}\n\
+         # HELP wormhole_horizons_connected Number of connected Horizons.\n\
+         # TYPE wormhole_horizons_connected gauge\n\
+         wormhole_horizons_connected {horizons_connected}\n\
+         # HELP wormhole_voyagers_connected Number of connected Voyagers.\n\
+         # TYPE wormhole_voyagers_connected gauge\n\
+         wormhole_voyagers_connected {voyagers_connected}\n\
+         # HELP wormhole_messages_relayed_total Messages relayed between Horizon and Voyagers.\n\
+         # TYPE wormhole_messages_relayed_total counter\n\
+         wormhole_messages_relayed_total{{direction=\"horizon_to_voyager\"}} {h2v}\n\
+         wormhole_messages_relayed_total{{direction=\"voyager_to_horizon\"}} {v2h}\n\
+         # HELP wormhole_bytes_relayed_total Bytes relayed between Horizon and Voyagers.\n\
+         # TYPE wormhole_bytes_relayed_total counter\n\
+         wormhole_bytes_relayed_total {bytes}\n\
+         # HELP wormhole_horizon_offline_replies_total horizon_offline error replies sent to Voyagers.\n\
+         # TYPE wormhole_horizon_offline_replies_total counter\n\
+         wormhole_horizon_offline_replies_total {offline}\n",
+        sessions_active = registry_metrics.sessions_active,
+        horizons_connected = registry_metrics.horizons_connected,
+        voyagers_connected = registry_metrics.voyagers_connected,
+        h2v = metrics.messages_relayed_horizon_to_voyager.load(Ordering::Relaxed),
+        v2h = metrics.messages_relayed_voyager_to_horizon.load(Ordering::Relaxed),
+        bytes = metrics.bytes_relayed.load(Ordering::Relaxed),
+        offline = metrics.horizon_offline_replies.load(Ordering::Relaxed),
+    );
+
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    )
+        .into_response()
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
@@ -151,7 +1012,37 @@ async fn ws_handler(
         }
     }
 
-    ws.on_upgrade(move |socket| handle_socket(state, role, session, socket))
+    if role == Role::Voyager {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if state
+            .registry
+            .send(SessionCommand::CheckJoin {
+                id: session.clone().expect("voyager session checked above"),
+                join: params.join.clone(),
+                reply: reply_tx,
+            })
+            .is_err()
+        {
+            return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "registry unavailable")
+                .into_response();
+        }
+        match reply_rx.await.unwrap_or(JoinCheck::Allowed) {
+            JoinCheck::Allowed => {}
+            JoinCheck::SecretMismatch => {
+                return (axum::http::StatusCode::FORBIDDEN, "invalid join secret").into_response();
+            }
+        }
+    }
+
+    let claimed_voyager_id = params
+        .voyager_id
+        .as_deref()
+        .and_then(|s| Uuid::parse_str(s).ok());
+
+    let codec = Codec::from_param(params.codec.as_deref());
+    ws.on_upgrade(move |socket| {
+        handle_socket(state, role, session, claimed_voyager_id, codec, socket)
+    })
 }
 
 async fn list_sessions(
@@ -162,18 +1053,17 @@ async fn list_sessions(
         return (axum::http::StatusCode::UNAUTHORIZED, "invalid token").into_response();
     }
 
-    let sessions = state.sessions.lock().await;
-    let response = SessionsResponse {
-        sessions: sessions
-            .iter()
-            .map(|(session_id, session)| SessionStatus {
-                session: session_id.clone(),
-                horizon_connected: session.horizon.is_some(),
-                voyager_count: session.voyagers.len(),
-            })
-            .collect(),
-    };
-    axum::Json(response).into_response()
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .registry
+        .send(SessionCommand::List { reply: reply_tx })
+        .is_err()
+    {
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "registry unavailable")
+            .into_response();
+    }
+    let sessions = reply_rx.await.unwrap_or_default();
+    axum::Json(SessionsResponse { sessions }).into_response()
 }
 
 async fn get_session(
@@ -185,17 +1075,23 @@ async fn get_session(
         return (axum::http::StatusCode::UNAUTHORIZED, "invalid token").into_response();
     }
 
-    let sessions = state.sessions.lock().await;
-    let Some(session) = sessions.get(&session_id) else {
-        return (axum::http::StatusCode::NOT_FOUND, "not found").into_response();
-    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .registry
+        .send(SessionCommand::Get {
+            id: session_id,
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "registry unavailable")
+            .into_response();
+    }
 
-    axum::Json(SessionStatus {
-        session: session_id,
-        horizon_connected: session.horizon.is_some(),
-        voyager_count: session.voyagers.len(),
-    })
-    .into_response()
+    match reply_rx.await.ok().flatten() {
+        Some(status) => axum::Json(status).into_response(),
+        None => (axum::http::StatusCode::NOT_FOUND, "not found").into_response(),
+    }
 }
 
 async fn close_session(
@@ -207,61 +1103,115 @@ async fn close_session(
         return (axum::http::StatusCode::UNAUTHORIZED, "invalid token").into_response();
     }
 
-    let mut sessions = state.sessions.lock().await;
-    let Some(session) = sessions.remove(&session_id) else {
-        return (axum::http::StatusCode::NOT_FOUND, "not found").into_response();
-    };
-
-    if let Some(horizon) = session.horizon.as_ref() {
-        let _ = horizon.send(Message::Close(None));
-    }
-    for voyager in session.voyagers {
-        let _ = voyager.send(Message::Close(None));
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .registry
+        .send(SessionCommand::Close {
+            id: session_id,
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "registry unavailable")
+            .into_response();
     }
 
-    (axum::http::StatusCode::OK, "closed").into_response()
+    match reply_rx.await {
+        Ok(true) => (axum::http::StatusCode::OK, "closed").into_response(),
+        _ => (axum::http::StatusCode::NOT_FOUND, "not found").into_response(),
+    }
 }
 
-async fn handle_socket(state: AppState, role: Role, session_param: Option<String>, socket: WebSocket) {
+async fn handle_socket(
+    state: AppState,
+    role: Role,
+    session_param: Option<String>,
+    claimed_voyager_id: Option<Uuid>,
+    codec: Codec,
+    socket: WebSocket,
+) {
     let (mut sender, mut receiver) = socket.split();
     let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
 
-    // Determine session ID: use provided one or generate for Horizon
-    let session_id = {
-        let mut sessions = state.sessions.lock().await;
-        let id = match (&role, session_param) {
-            (Role::Horizon, None) => generate_session_id(&sessions),
-            (_, Some(s)) => s,
-            (Role::Voyager, None) => unreachable!(), // Already validated in ws_handler
-        };
-
-        let session = sessions.entry(id.clone()).or_insert_with(Session::new);
-        match role {
-            Role::Horizon => {
-                if session.horizon.is_some() {
-                    warn!(session_id = %id, "horizon replaced existing connection");
-                }
-                session.horizon = Some(tx.clone());
-            }
-            Role::Voyager => {
-                session.voyagers.push(tx.clone());
-            }
-        }
-        id
+    // Determine session ID: use provided one, or generated/reattached by the
+    // registry. For a Voyager the registry also decides the actual
+    // `voyager_id` here: it reclaims `claimed_voyager_id` if that id still
+    // owns a live detach slot, otherwise it mints a fresh one.
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .registry
+        .send(SessionCommand::Register {
+            id: session_param,
+            role,
+            claimed_voyager_id,
+            codec,
+            tx: tx.clone(),
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        warn!("session registry unavailable");
+        return;
+    }
+    let Ok(RegisterAck {
+        session_id,
+        voyager_id,
+        over_capacity,
+    }) = reply_rx.await
+    else {
+        warn!("session registry dropped reply");
+        return;
     };
 
-    info!(session_id = %session_id, ?role, "client connected");
+    if over_capacity {
+        warn!(session_id = %session_id, "rejecting voyager: session already at max_voyagers");
+        let _ = sender
+            .send(encode_frame(
+                codec,
+                &serde_json::json!({
+                    "v": 1,
+                    "type": "error",
+                    "code": "session_full",
+                }),
+            ))
+            .await;
+        let _ = sender.send(Message::Close(None)).await;
+        return;
+    }
+
+    info!(session_id = %session_id, ?role, ?voyager_id, ?codec, "client connected");
 
     // Send session_assigned message to Horizon
     if role == Role::Horizon {
-        let assign_msg = serde_json::json!({
-            "v": 1,
-            "type": "session_assigned",
-            "sessionId": session_id
-        });
-        if sender.send(Message::Text(assign_msg.to_string())).await.is_err() {
+        let assign_msg = encode_frame(
+            codec,
+            &serde_json::json!({
+                "v": 1,
+                "type": "session_assigned",
+                "sessionId": session_id
+            }),
+        );
+        if sender.send(assign_msg).await.is_err() {
             warn!(session_id = %session_id, "failed to send session_assigned");
-            cleanup_connection(state, role, &session_id, &tx).await;
+            cleanup_connection(state, role, &session_id, voyager_id, &tx).await;
+            return;
+        }
+    }
+
+    // Tell a Voyager its assigned id so it can present it via `?voyager_id=`
+    // on reconnect and reclaim whatever was buffered for it in the meantime.
+    if let Some(voyager_id) = voyager_id {
+        let assign_msg = encode_frame(
+            codec,
+            &serde_json::json!({
+                "v": 1,
+                "type": "voyager_assigned",
+                "id": voyager_id.to_string(),
+            }),
+        );
+        if sender.send(assign_msg).await.is_err() {
+            warn!(session_id = %session_id, "failed to send voyager_assigned");
+            cleanup_connection(state, role, &session_id, Some(voyager_id), &tx).await;
             return;
         }
     }
@@ -275,14 +1225,40 @@ async fn handle_socket(state: AppState, role: Role, session_param: Option<String
                 break;
             }
         }
-        cleanup_connection(state_for_send, role, &session_id_for_send, &tx_for_send).await;
+        cleanup_connection(state_for_send, role, &session_id_for_send, voyager_id, &tx_for_send).await;
     });
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        route_message(state.clone(), role, &session_id, msg, Some(tx.clone())).await;
+    // Pings keep idle-but-alive connections (e.g. through proxies that drop
+    // silent TCP streams) open; if neither a Pong nor any other frame arrives
+    // within the pong timeout, the connection is treated as dead.
+    let mut last_seen = Instant::now();
+    let mut ping_ticker = tokio::time::interval(ping_interval());
+    ping_ticker.tick().await;
+    let pong_timeout = pong_timeout();
+
+    loop {
+        tokio::select! {
+            maybe_msg = receiver.next() => {
+                let Some(Ok(msg)) = maybe_msg else { break };
+                last_seen = Instant::now();
+                if matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+                    continue;
+                }
+                route_message(state.clone(), role, &session_id, voyager_id, msg, Some(tx.clone())).await;
+            }
+            _ = ping_ticker.tick() => {
+                if last_seen.elapsed() > pong_timeout {
+                    warn!(session_id = %session_id, ?role, "no traffic within pong timeout, closing connection");
+                    break;
+                }
+                if tx.send(Message::Ping(Vec::new())).is_err() {
+                    break;
+                }
+            }
+        }
     }
 
-    cleanup_connection(state.clone(), role, &session_id, &tx).await;
+    cleanup_connection(state.clone(), role, &session_id, voyager_id, &tx).await;
     send_task.abort();
     info!(session_id = %session_id, ?role, "client disconnected");
 }
@@ -291,91 +1267,74 @@ async fn route_message(
     state: AppState,
     role: Role,
     session_id: &str,
+    voyager_id: Option<Uuid>,
     msg: Message,
     origin: Option<mpsc::UnboundedSender<Message>>,
 ) {
-    let mut sessions = state.sessions.lock().await;
-    let Some(session) = sessions.get_mut(session_id) else {
-        return;
-    };
-
-    match role {
-        Role::Horizon => {
-            session.voyagers.retain(|tx| tx.send(msg.clone()).is_ok());
-        }
-        Role::Voyager => {
-            if let Some(horizon) = session.horizon.as_ref() {
-                if horizon.send(msg).is_err() {
-                    session.horizon = None;
-                }
-            } else {
-                if let Some(origin) = origin.as_ref() {
-                    if let Some(reply) = build_no_horizon_reply(&msg) {
-                        let _ = origin.send(reply);
-                    }
-                }
-            }
-        }
-    }
+    let _ = state.registry.send(SessionCommand::Route {
+        id: session_id.to_string(),
+        role,
+        voyager_id,
+        msg,
+        origin,
+    });
 }
 
 async fn cleanup_connection(
     state: AppState,
     role: Role,
     session_id: &str,
+    voyager_id: Option<Uuid>,
     tx: &mpsc::UnboundedSender<Message>,
 ) {
-    let mut sessions = state.sessions.lock().await;
-    let Some(session) = sessions.get_mut(session_id) else {
-        return;
-    };
+    let _ = state.registry.send(SessionCommand::Remove {
+        id: session_id.to_string(),
+        role,
+        voyager_id,
+        tx: tx.clone(),
+    });
+}
 
-    match role {
-        Role::Horizon => {
-            if let Some(horizon) = session.horizon.as_ref() {
-                if horizon.same_channel(tx) {
-                    session.horizon = None;
-                }
-            }
-        }
-        Role::Voyager => {
-            session.voyagers.retain(|voyager_tx| !voyager_tx.same_channel(tx));
-        }
+/// Parses a `Message` as a control-frame object, decoding `Text` as JSON and
+/// `Binary` as MsgPack so both codecs are inspected the same way.
+fn parse_frame(msg: &Message) -> Option<Map<String, Value>> {
+    let value = match msg {
+        Message::Text(text) => serde_json::from_str::<Value>(text).ok()?,
+        Message::Binary(data) => rmp_serde::from_slice::<Value>(data).ok()?,
+        _ => return None,
+    };
+    match value {
+        Value::Object(map) => Some(map),
+        _ => None,
     }
+}
 
-    if session.horizon.is_none() && session.voyagers.is_empty() {
-        sessions.remove(session_id);
+/// Reads the `"type"` field out of a parsed frame, if present.
+fn frame_type(map: &Map<String, Value>) -> Option<&str> {
+    match map.get("type") {
+        Some(Value::String(typ)) => Some(typ.as_str()),
+        _ => None,
     }
 }
 
-fn build_no_horizon_reply(msg: &Message) -> Option<Message> {
-    let Message::Text(text) = msg else {
-        return None;
-    };
-    let Ok(value) = serde_json::from_str::<Value>(text) else {
-        return None;
-    };
-    let Value::Object(map) = value else {
-        return None;
-    };
-    let Some(Value::String(typ)) = map.get("type") else {
-        return None;
-    };
+fn build_no_horizon_reply(msg: &Message, codec: Codec) -> Option<Message> {
+    let map = parse_frame(msg)?;
+    let typ = frame_type(&map)?;
     let is_control = matches!(
-        typ.as_str(),
-        "list" | "create" | "close" | "stdin" | "resize"
+        typ,
+        "list" | "create" | "close" | "stdin" | "resize" | crypto::KX_INIT | crypto::KX_REPLY | crypto::DATA
     );
     if !is_control {
         return None;
     }
-    Some(Message::Text(
-        serde_json::json!({
+    Some(encode_frame(
+        codec,
+        &serde_json::json!({
             "v": 1,
             "type": "error",
             "code": "horizon_offline",
             "message": "Horizon is not connected for this session"
-        })
-        .to_string(),
+        }),
     ))
 }
 
@@ -385,3 +1344,276 @@ fn token_valid(state: &AppState, token: Option<&str>) -> bool {
         None => true,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_registry() -> mpsc::UnboundedSender<SessionCommand> {
+        spawn_registry(
+            Duration::from_millis(50),
+            Duration::from_secs(3600),
+            Arc::new(Metrics::default()),
+        )
+    }
+
+    async fn register_horizon(
+        registry: &mpsc::UnboundedSender<SessionCommand>,
+        id: Option<&str>,
+    ) -> (
+        String,
+        mpsc::UnboundedSender<Message>,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (reply, reply_rx) = oneshot::channel();
+        registry
+            .send(SessionCommand::Register {
+                id: id.map(str::to_string),
+                role: Role::Horizon,
+                claimed_voyager_id: None,
+                codec: Codec::Json,
+                tx: tx.clone(),
+                reply,
+            })
+            .unwrap();
+        let ack = reply_rx.await.unwrap();
+        (ack.session_id, tx, rx)
+    }
+
+    async fn register_voyager(
+        registry: &mpsc::UnboundedSender<SessionCommand>,
+        id: &str,
+        claimed_voyager_id: Option<Uuid>,
+    ) -> (RegisterAck, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (reply, reply_rx) = oneshot::channel();
+        registry
+            .send(SessionCommand::Register {
+                id: Some(id.to_string()),
+                role: Role::Voyager,
+                claimed_voyager_id,
+                codec: Codec::Json,
+                tx,
+                reply,
+            })
+            .unwrap();
+        (reply_rx.await.unwrap(), rx)
+    }
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.to_string())
+    }
+
+    #[tokio::test]
+    async fn horizon_broadcast_is_relayed_to_voyager() {
+        let registry = test_registry();
+        let (session_id, _horizon_tx, _horizon_rx) = register_horizon(&registry, None).await;
+        let (_ack, mut voyager_rx) = register_voyager(&registry, &session_id, None).await;
+
+        registry
+            .send(SessionCommand::Route {
+                id: session_id,
+                role: Role::Horizon,
+                voyager_id: None,
+                msg: text(r#"{"type":"hello"}"#),
+                origin: None,
+            })
+            .unwrap();
+
+        let received = voyager_rx.recv().await.unwrap();
+        assert!(matches!(received, Message::Text(t) if t.contains("hello")));
+    }
+
+    #[tokio::test]
+    async fn voyager_message_is_tagged_with_from_and_relayed_to_horizon() {
+        let registry = test_registry();
+        let (session_id, _horizon_tx, mut horizon_rx) = register_horizon(&registry, None).await;
+        let (ack, _voyager_rx) = register_voyager(&registry, &session_id, None).await;
+        let voyager_id = ack.voyager_id.unwrap();
+
+        // The Horizon is told about the join before any relayed frame.
+        let joined = horizon_rx.recv().await.unwrap();
+        assert!(matches!(joined, Message::Text(t) if t.contains("voyager_joined")));
+
+        registry
+            .send(SessionCommand::Route {
+                id: session_id,
+                role: Role::Voyager,
+                voyager_id: Some(voyager_id),
+                msg: text(r#"{"type":"stdin","data":"ls\n"}"#),
+                origin: None,
+            })
+            .unwrap();
+
+        let received = horizon_rx.recv().await.unwrap();
+        let Message::Text(t) = received else {
+            panic!("expected a text frame");
+        };
+        let frame: Value = serde_json::from_str(&t).unwrap();
+        assert_eq!(frame["type"], "stdin");
+        assert_eq!(frame["from"], voyager_id.to_string());
+    }
+
+    #[tokio::test]
+    async fn horizon_reconnect_within_grace_window_drains_buffered_messages() {
+        let registry = test_registry();
+        let (session_id, horizon_tx, horizon_rx) = register_horizon(&registry, None).await;
+        let (ack, _voyager_rx) = register_voyager(&registry, &session_id, None).await;
+        let voyager_id = ack.voyager_id.unwrap();
+
+        // Horizon drops; it should enter its reconnect grace window rather
+        // than tearing the session down immediately.
+        registry
+            .send(SessionCommand::Remove {
+                id: session_id.clone(),
+                role: Role::Horizon,
+                voyager_id: None,
+                tx: horizon_tx,
+            })
+            .unwrap();
+        drop(horizon_rx);
+
+        // A message sent while the Horizon is detached should be buffered,
+        // not dropped.
+        registry
+            .send(SessionCommand::Route {
+                id: session_id.clone(),
+                role: Role::Voyager,
+                voyager_id: Some(voyager_id),
+                msg: text(r#"{"type":"stdin","data":"echo hi\n"}"#),
+                origin: None,
+            })
+            .unwrap();
+
+        // Reconnect well within the 50ms grace window configured by
+        // test_registry, and the buffered message should be drained to it.
+        let (_session_id2, _new_tx, mut new_horizon_rx) =
+            register_horizon(&registry, Some(&session_id)).await;
+        let received = new_horizon_rx.recv().await.unwrap();
+        let Message::Text(t) = received else {
+            panic!("expected a text frame");
+        };
+        assert!(t.contains("echo hi"));
+    }
+
+    #[tokio::test]
+    async fn reconnecting_voyager_only_drains_its_own_buffer() {
+        let registry = test_registry();
+        let (session_id, _horizon_tx, _horizon_rx) = register_horizon(&registry, None).await;
+
+        let (ack_a, rx_a) = register_voyager(&registry, &session_id, None).await;
+        let voyager_a = ack_a.voyager_id.unwrap();
+        let (ack_b, rx_b) = register_voyager(&registry, &session_id, None).await;
+        let voyager_b = ack_b.voyager_id.unwrap();
+
+        // Both Voyagers drop and enter their own grace windows.
+        registry
+            .send(SessionCommand::Remove {
+                id: session_id.clone(),
+                role: Role::Voyager,
+                voyager_id: Some(voyager_a),
+                tx: mpsc::unbounded_channel().0,
+            })
+            .unwrap();
+        drop(rx_a);
+        registry
+            .send(SessionCommand::Remove {
+                id: session_id.clone(),
+                role: Role::Voyager,
+                voyager_id: Some(voyager_b),
+                tx: mpsc::unbounded_channel().0,
+            })
+            .unwrap();
+        drop(rx_b);
+
+        // A reply targeted at Voyager A specifically should only end up in
+        // A's buffer, never B's.
+        registry
+            .send(SessionCommand::Route {
+                id: session_id.clone(),
+                role: Role::Horizon,
+                voyager_id: None,
+                msg: text(&format!(
+                    r#"{{"type":"stdout","to":"{voyager_a}","data":"for a"}}"#
+                )),
+                origin: None,
+            })
+            .unwrap();
+
+        // A unrelated third Voyager joining during the grace window must
+        // not be handed either buffered message.
+        let (ack_c, mut rx_c) = register_voyager(&registry, &session_id, None).await;
+        assert_ne!(ack_c.voyager_id, Some(voyager_a));
+        assert_ne!(ack_c.voyager_id, Some(voyager_b));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), rx_c.recv())
+                .await
+                .is_err(),
+            "an unrelated voyager must not receive another voyager's buffered message"
+        );
+
+        // Voyager A reconnecting with its own claimed id reclaims only its
+        // own buffered message.
+        let (ack_a2, mut rx_a2) =
+            register_voyager(&registry, &session_id, Some(voyager_a)).await;
+        assert_eq!(ack_a2.voyager_id, Some(voyager_a));
+        let received = rx_a2.recv().await.unwrap();
+        let Message::Text(t) = received else {
+            panic!("expected a text frame");
+        };
+        assert!(t.contains("for a"));
+    }
+
+    #[tokio::test]
+    async fn voyager_sent_set_join_secret_is_a_no_op() {
+        let registry = test_registry();
+        let (session_id, _horizon_tx, _horizon_rx) = register_horizon(&registry, None).await;
+        let (ack, _voyager_rx) = register_voyager(&registry, &session_id, None).await;
+        let voyager_id = ack.voyager_id.unwrap();
+
+        registry
+            .send(SessionCommand::Route {
+                id: session_id.clone(),
+                role: Role::Voyager,
+                voyager_id: Some(voyager_id),
+                msg: text(r#"{"type":"set_join_secret","secret":"hunter2"}"#),
+                origin: None,
+            })
+            .unwrap();
+
+        let (reply, reply_rx) = oneshot::channel();
+        registry
+            .send(SessionCommand::CheckJoin {
+                id: session_id,
+                join: None,
+                reply,
+            })
+            .unwrap();
+        assert_eq!(reply_rx.await.unwrap(), JoinCheck::Allowed);
+    }
+
+    #[tokio::test]
+    async fn max_voyagers_cap_is_enforced_atomically_in_register() {
+        let registry = test_registry();
+        let (session_id, _horizon_tx, _horizon_rx) = register_horizon(&registry, None).await;
+
+        registry
+            .send(SessionCommand::Route {
+                id: session_id.clone(),
+                role: Role::Horizon,
+                voyager_id: None,
+                msg: text(r#"{"type":"set_join_secret","max_voyagers":1}"#),
+                origin: None,
+            })
+            .unwrap();
+
+        let (ack_first, _rx_first) = register_voyager(&registry, &session_id, None).await;
+        assert!(ack_first.voyager_id.is_some());
+        assert!(!ack_first.over_capacity);
+
+        let (ack_second, _rx_second) = register_voyager(&registry, &session_id, None).await;
+        assert!(ack_second.voyager_id.is_none());
+        assert!(ack_second.over_capacity);
+    }
+}